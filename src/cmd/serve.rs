@@ -21,17 +21,27 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::fs::{File};
+use std::fs::{remove_dir_all, File};
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use actix_web::{self, fs, http, server, App, HttpRequest, HttpResponse, Responder};
+use actix_web::actix::{Addr, System};
 use actix_web::middleware::{Middleware, Started, Response, Logger};
+use actix_web::server::{Server, StopServer};
+use ctrlc;
+use open;
 use ws::{WebSocket, Sender, Message};
 use utils::net::get_available_port;
 
+/// How many bind attempts (the requested port included) we'll make before
+/// giving up on binding the HTTP server.
+const MAX_PORT_ATTEMPTS: u8 = 10;
+
 use errors::{Result};
 use console;
 use super::watch;
@@ -95,10 +105,16 @@ fn handle_directory<'a, 'b>(dir: &'a fs::Directory, req: &'b HttpRequest) -> io:
     fs::NamedFile::open(path)?.respond_to(req)
 }
 
-pub fn serve(interface: &str, port: &str, output_dir: &str, _base_url: &str, config_file: &str) -> Result<()> {
+pub fn serve(interface: &str, port: &str, output_dir: &str, _base_url: &str, config_file: &str, open: bool) -> Result<()> {
     println!("serve {}, {}, {}, {}, {}", interface, port, output_dir, _base_url, config_file);
     let (tx, rx) = channel();
 
+    // Shared shutdown signal: set by the Ctrl+C handler below and polled by
+    // the watch loop and this function's own message loop so every thread
+    // gets a chance to wind down cleanly instead of the process being killed
+    // out from under a half-finished rebuild.
+    let shutdown = Arc::new(AtomicBool::new(false));
+
     let ws_address = format!("{}:{}", interface, get_available_port().unwrap());
     println!("ws_address: {}", ws_address);
     let output_path = Path::new(output_dir).to_path_buf();
@@ -110,36 +126,90 @@ pub fn serve(interface: &str, port: &str, output_dir: &str, _base_url: &str, con
     let base_url = address.clone();
     let config_file = config_file.to_string();
     let output_dir = output_dir.to_string();
-    thread::spawn(move || {
-        watch::watch(&output_dir, &base_url, &config_file, &Some(tx)).unwrap();
+    let watch_shutdown = shutdown.clone();
+    let watch_handle = thread::spawn(move || {
+        // `serve()` already installs a single Ctrl+C handler shared across
+        // all of its threads (see below), so `watch()` must not install its
+        // own.
+        watch::watch(&output_dir, &base_url, &config_file, &Some(tx), watch_shutdown, false).unwrap();
     });
     //wait for the first build to complete
     rx.recv().unwrap();
 
-    thread::spawn(move || {
-
+    // Filled in by the server thread once it has bound successfully, so we
+    // have a handle to stop it gracefully from the shutdown path below.
+    let server_addr: Arc<Mutex<Option<Addr<Server>>>> = Arc::new(Mutex::new(None));
+    let http_server_addr = server_addr.clone();
+    // Filled in alongside `server_addr` with the address we actually managed
+    // to bind, so the `--open` flag opens a URL that is guaranteed to work.
+    let bound_address: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let http_bound_address = bound_address.clone();
+    let interface = interface.to_string();
+    let http_handle = thread::spawn(move || {
         println!("starting server, static_root: {:?}", static_root);
-        let s = server::new(move || {
-            App::new()
-            .middleware(Logger::default())
-            .middleware(NotFoundHandler { rendered_template: static_root.join("404.html") })
-            .resource(r"/livereload.js", |r| r.f(livereload_handler))
-            // Start a webserver that serves the `output_dir` directory
-            .handler(
-                r"/",
-                fs::StaticFiles::new(&static_root)
-                    .unwrap()
-                    .show_files_listing()
-                    .files_listing_renderer(handle_directory)
-            )
-        })
-        .bind(&address)
-        .expect("Can't start the webserver")
-        .shutdown_timeout(20);
-        println!("Web server is available at http://{}", &address);
-        s.run();
+        let sys = System::new("gutenberg-server");
+
+        let mut candidate_address = address;
+        let mut srv = None;
+        for attempt in 0..MAX_PORT_ATTEMPTS {
+            let root = static_root.clone();
+            match server::new(move || {
+                App::new()
+                .middleware(Logger::default())
+                .middleware(NotFoundHandler { rendered_template: root.join("404.html") })
+                .resource(r"/livereload.js", |r| r.f(livereload_handler))
+                // Start a webserver that serves the `output_dir` directory
+                .handler(
+                    r"/",
+                    fs::StaticFiles::new(&root)
+                        .unwrap()
+                        .show_files_listing()
+                        .files_listing_renderer(handle_directory)
+                )
+            }).bind(&candidate_address) {
+                Ok(s) => {
+                    srv = Some(s);
+                    break;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && attempt + 1 < MAX_PORT_ATTEMPTS => {
+                    let fallback_port = get_available_port().expect("No available port found");
+                    console::warn(&format!("Port {} is already in use, trying {}...", candidate_address, fallback_port));
+                    candidate_address = format!("{}:{}", interface, fallback_port);
+                },
+                Err(e) => panic!("Can't start the webserver: {}", e),
+            }
+        }
+
+        // `system_exit()` ensures that once the server actor is stopped (via
+        // the `StopServer` message sent from the shutdown path below), the
+        // `System` running this thread's event loop stops too -- otherwise
+        // `sys.run()` would block forever and `http_handle.join()` would
+        // never return.
+        let srv = srv.expect("Can't start the webserver: no available port found")
+            .shutdown_timeout(20)
+            .system_exit()
+            .start();
+        *http_server_addr.lock().unwrap() = Some(srv);
+        *http_bound_address.lock().unwrap() = Some(candidate_address.clone());
+        println!("Web server is available at http://{}", &candidate_address);
+        let _ = sys.run();
     });
 
+    if open {
+        let opened_address = bound_address.clone();
+        thread::spawn(move || {
+            // Wait for the server thread to have successfully bound before
+            // opening the browser.
+            loop {
+                if let Some(ref addr) = *opened_address.lock().unwrap() {
+                    let _ = open::that(format!("http://{}", addr));
+                    break;
+                }
+                thread::sleep(::std::time::Duration::from_millis(50));
+            }
+        });
+    }
+
     // The websocket for livereload
     let ws_server = WebSocket::new(|output: Sender| {
         move |msg: Message| {
@@ -157,18 +227,41 @@ pub fn serve(interface: &str, port: &str, output_dir: &str, _base_url: &str, con
     }).expect("Failed to create ws server");
     println!("starting ws server");
     let broadcaster = ws_server.broadcaster();
-    thread::spawn(move || {
+    let ws_shutdown_handle = ws_server.broadcaster();
+    let ws_handle = thread::spawn(move || {
         ws_server.listen(&*ws_address).unwrap();
     });
 
+    let shutdown_signal = shutdown.clone();
+    ctrlc::set_handler(move || {
+        shutdown_signal.store(true, Ordering::SeqCst);
+    }).expect("Error setting Ctrl-C handler");
+
     loop {
-        match rx.recv() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match rx.recv_timeout(::std::time::Duration::from_millis(200)) {
             Ok(msg) => {
                 broadcaster.send(msg).unwrap();
             },
-            Err(e) => console::error(&format!("Watch error: {:?}", e)),
+            Err(::std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(::std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
         };
     }
+
+    console::info("Shutting down...");
+    if let Some(srv) = server_addr.lock().unwrap().take() {
+        srv.do_send(StopServer { graceful: true });
+    }
+    let _ = ws_shutdown_handle.shutdown();
+    let _ = watch_handle.join();
+    let _ = http_handle.join();
+    let _ = ws_handle.join();
+    remove_dir_all(&output_path).expect("Failed to delete output directory");
+
+    Ok(())
 }
 
 
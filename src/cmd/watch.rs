@@ -1,12 +1,16 @@
+use std::collections::HashSet;
 use std::env;
-use std::fs::{remove_dir_all};
+use std::fs::remove_dir_all;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::sync::Arc;
 use std::time::{Instant, Duration};
 
 use chrono::prelude::*;
-use notify::{Watcher, RecursiveMode, watcher};
 use ctrlc;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify::{Watcher, RecursiveMode, watcher};
 
 use site::Site;
 use errors::{Result, ResultExt};
@@ -15,6 +19,14 @@ use utils::fs::copy_file;
 use console;
 use rebuild;
 
+/// How often we poll for new filesystem events while also checking whether
+/// we have been asked to shut down.
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Once an event arrives, how long to keep draining the channel for more
+/// before rebuilding, so a burst of saves collapses into one rebuild.
+const EVENT_BATCH_WINDOW: Duration = Duration::from_millis(200);
+
 #[derive(Debug, PartialEq)]
 enum ChangeKind {
     Content,
@@ -44,6 +56,20 @@ fn rebuild_done_handling(broadcaster: &Option<Sender<String>>, res: Result<()>,
     }
 }
 
+/// Compiles the `ignored_content` glob patterns from the site config into a
+/// single `GlobSet`, skipping (and warning about) any pattern that fails to
+/// parse rather than aborting the whole watch.
+fn build_ignored_globset(patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => { builder.add(glob); },
+            Err(e) => console::error(&format!("Invalid ignored_content glob `{}`: {}", pattern, e)),
+        }
+    }
+    builder.build().unwrap_or_else(|_| GlobSetBuilder::new().build().unwrap())
+}
+
 fn create_new_site(output_dir: &str, base_url: &str, config_file: &str) -> Result<Site> {
     let mut site = Site::new(env::current_dir().unwrap(), config_file)?;
     site.set_base_url(base_url.to_string());
@@ -55,7 +81,25 @@ fn create_new_site(output_dir: &str, base_url: &str, config_file: &str) -> Resul
     Ok(site)
 }
 
-pub fn watch(output_dir: &str, base_url: &str, config_file: &str, broadcaster: &Option<Sender<String>>) -> Result<()> {
+/// Watches `content/`, `templates/`, the config file, `static/` (if it
+/// exists) and `sass/` (if it exists and `compile_sass` is on) for changes
+/// and rebuilds as needed until `shutdown` is set. Changes under
+/// `output_dir` are skipped.
+///
+/// `own_ctrlc_handler` should be `true` when this is the only thing running
+/// (the standalone `gutenberg watch` command): it installs its own Ctrl+C
+/// handler that flips `shutdown` and removes `output_dir` once the loop
+/// exits. When `watch()` is driven from `serve()`, which already installs a
+/// single handler shared across all of its threads, pass `false` so we don't
+/// try to register a second global Ctrl+C handler.
+pub fn watch(output_dir: &str, base_url: &str, config_file: &str, broadcaster: &Option<Sender<String>>, shutdown: Arc<AtomicBool>, own_ctrlc_handler: bool) -> Result<()> {
+    if own_ctrlc_handler {
+        let ctrlc_shutdown = shutdown.clone();
+        ctrlc::set_handler(move || {
+            ctrlc_shutdown.store(true, Ordering::SeqCst);
+        }).expect("Error setting Ctrl-C handler");
+    }
+
     let start = Instant::now();
     let mut site = create_new_site(output_dir, base_url, config_file)?;
     if let Some(ref broadcaster) = broadcaster {
@@ -81,9 +125,16 @@ pub fn watch(output_dir: &str, base_url: &str, config_file: &str, broadcaster: &
     }
 
     // Sass support is optional so don't make it an error to no have a sass folder
-    let _ = watcher.watch("sass/", RecursiveMode::Recursive);
+    let mut watching_sass = site.config.compile_sass && Path::new("sass").exists();
+    if watching_sass {
+        watching_sass = watcher.watch("sass/", RecursiveMode::Recursive).is_ok();
+    }
 
-    let output_path = Path::new(output_dir).to_path_buf();
+    let mut ignored_globset = build_ignored_globset(&site.config.ignored_content);
+    // If the output directory overlaps one of the watched folders (or a user
+    // points it there by mistake), never treat its own writes as a change to
+    // rebuild from.
+    let canonical_output_path = Path::new(output_dir).canonicalize().ok();
 
     let pwd = env::current_dir().unwrap();
 
@@ -91,69 +142,182 @@ pub fn watch(output_dir: &str, base_url: &str, config_file: &str, broadcaster: &
     if watching_static {
         watchers.push("static");
     }
-    if site.config.compile_sass {
+    if watching_sass {
         watchers.push("sass");
     }
 
     println!("Listening for changes in {}/{{{}}}", pwd.display(), watchers.join(", "));
 
     println!("Press Ctrl+C to stop\n");
-    // Delete the output folder on ctrl+C
-    ctrlc::set_handler(move || {
-        remove_dir_all(&output_path).expect("Failed to delete output directory");
-        ::std::process::exit(0);
-    }).expect("Error setting Ctrl-C handler");
 
     use notify::DebouncedEvent::*;
 
     loop {
-        match rx.recv() {
-            Ok(event) => {
-                match event {
-                    Create(path) |
-                    Write(path) |
-                    Remove(path) |
-                    Rename(_, path) => {
-                        if is_temp_file(&path) || path.is_dir() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let first_event = match rx.recv_timeout(RECV_POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let mut events = vec![first_event];
+        // Saving ten files at once (or an editor's write-rename-write dance)
+        // would otherwise trigger one rebuild per event. Drain the channel
+        // for a short window and collapse the burst into a single rebuild.
+        while let Ok(event) = rx.recv_timeout(EVENT_BATCH_WINDOW) {
+            events.push(event);
+        }
+
+        let mut changed_paths = HashSet::new();
+        for event in events {
+            match event {
+                Create(path) |
+                Write(path) |
+                Remove(path) |
+                Rename(_, path) => {
+                    if is_temp_file(&path) || path.is_dir() {
+                        continue;
+                    }
+
+                    if ignored_globset.is_match(&path) {
+                        continue;
+                    }
+
+                    if let Some(ref canonical_output_path) = canonical_output_path {
+                        if path.canonicalize().map(|p| p.starts_with(canonical_output_path)).unwrap_or(false) {
                             continue;
                         }
+                    }
 
-                        println!("Change detected @ {}", Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
-                        let start = Instant::now();
-                        match detect_change_kind(&pwd, &path) {
-                            (ChangeKind::Content, _) => {
-                                console::info(&format!("-> Content changed {}", path.display()));
-                                // Force refresh
-                                rebuild_done_handling(&broadcaster, rebuild::after_content_change(&mut site, &path), "/x.js");
-                            },
-                            (ChangeKind::Templates, _) => {
-                                console::info(&format!("-> Template changed {}", path.display()));
-                                // Force refresh
-                                rebuild_done_handling(&broadcaster, rebuild::after_template_change(&mut site, &path), "/x.js");
-                            },
-                            (ChangeKind::StaticFiles, p) => {
-                                if path.is_file() {
-                                    console::info(&format!("-> Static file changes detected {}", path.display()));
-                                    rebuild_done_handling(&broadcaster, copy_file(&path, &site.output_path, &site.static_path), &p.to_string_lossy());
-                                }
-                            },
-                            (ChangeKind::Sass, p) => {
-                                console::info(&format!("-> Sass file changed {}", path.display()));
-                                rebuild_done_handling(&broadcaster, site.compile_sass(&site.base_path), &p.to_string_lossy());
-                            },
-                            (ChangeKind::Config, _) => {
-                                console::info(&format!("-> Config changed. The whole site will be reloaded. The browser needs to be refreshed to make the changes visible."));
-                                site = create_new_site(output_dir, base_url, config_file).unwrap();
-                            }
-                        };
-                        console::report_elapsed_time(start);
+                    changed_paths.insert(path);
+                },
+                _ => {}
+            }
+        }
+
+        if changed_paths.is_empty() {
+            continue;
+        }
+
+        // Resolve every changed path to a `ChangeKind` up front so we can
+        // decide how to order the batch before doing any rebuild work.
+        let mut detected = Vec::new();
+        for path in &changed_paths {
+            match detect_change_kind(&pwd, path) {
+                Some((change_kind, partial_path)) => detected.push((path, change_kind, partial_path)),
+                None => console::warn(&format!("Ignoring change in an unexpected path: {}", path.display())),
+            }
+        }
+
+        if detected.is_empty() {
+            continue;
+        }
+
+        println!("Change(s) detected @ {}", Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+        let start = Instant::now();
+
+        let mut result: Result<()> = Ok(());
+        let mut reload_path: Option<String> = None;
+        let mut full_reload = false;
+
+        // A config change invalidates the rest of the batch: `create_new_site`
+        // already does a full rebuild, so rebuilding individual content/Sass
+        // changes against the soon-to-be-replaced `site` (or the other way
+        // around, since `changed_paths` is a `HashSet` with no ordering
+        // guarantee) would just be thrown away. Handle it on its own and
+        // skip everything else in this batch.
+        if detected.iter().any(|&(_, ref change_kind, _)| *change_kind == ChangeKind::Config) {
+            console::info(&format!("-> Config changed. The whole site will be reloaded. The browser needs to be refreshed to make the changes visible."));
+            match create_new_site(output_dir, base_url, config_file) {
+                Ok(new_site) => {
+                    site = new_site;
+                    ignored_globset = build_ignored_globset(&site.config.ignored_content);
+
+                    // The config may have just turned `compile_sass`
+                    // on/off, or the user may have added/removed a
+                    // `static` folder: re-arm the watchers to match
+                    // rather than requiring a server restart.
+                    let static_exists = Path::new("static").exists();
+                    if static_exists && !watching_static {
+                        watching_static = watcher.watch("static/", RecursiveMode::Recursive).is_ok();
+                    } else if !static_exists && watching_static {
+                        let _ = watcher.unwatch("static/");
+                        watching_static = false;
+                    }
+
+                    let sass_should_be_watched = site.config.compile_sass && Path::new("sass").exists();
+                    if sass_should_be_watched && !watching_sass {
+                        watching_sass = watcher.watch("sass/", RecursiveMode::Recursive).is_ok();
+                    } else if !sass_should_be_watched && watching_sass {
+                        let _ = watcher.unwatch("sass/");
+                        watching_sass = false;
                     }
-                    _ => {}
+
+                    full_reload = true;
+                },
+                Err(e) => {
+                    // Keep the previous, working `site` alive and don't tell
+                    // the browser to reload: the edit never took effect.
+                    result = Err(e);
                 }
-            },
-            Err(e) => console::error(&format!("Watch error: {:?}", e)),
+            }
+        } else {
+            for (path, change_kind, partial_path) in detected {
+                match change_kind {
+                    ChangeKind::Content => {
+                        console::info(&format!("-> Content changed {}", path.display()));
+                        result = result.and(rebuild::after_content_change(&mut site, path));
+                        full_reload = true;
+                    },
+                    ChangeKind::Templates => {
+                        console::info(&format!("-> Template changed {}", path.display()));
+                        result = result.and(rebuild::after_template_change(&mut site, path));
+                        full_reload = true;
+                    },
+                    ChangeKind::StaticFiles => {
+                        if path.is_file() {
+                            console::info(&format!("-> Static file changes detected {}", path.display()));
+                            result = result.and(copy_file(path, &site.output_path, &site.static_path));
+                            if !full_reload && reload_path.is_none() {
+                                reload_path = Some(reload_url(&ChangeKind::StaticFiles, &partial_path));
+                            }
+                        }
+                    },
+                    ChangeKind::Sass => {
+                        console::info(&format!("-> Sass file changed {}", path.display()));
+                        result = result.and(site.compile_sass(&site.base_path));
+                        if !full_reload && reload_path.is_none() {
+                            reload_path = Some(reload_url(&ChangeKind::Sass, &partial_path));
+                        }
+                    },
+                    ChangeKind::Config => unreachable!("Config changes are handled before this loop"),
+                };
+            }
+        }
+
+        // A content/template/config change always forces a full reload, even
+        // if an earlier Sass/static change in this same batch had already
+        // picked a more targeted `reload_path`.
+        let final_reload_path = if full_reload {
+            "/x.js".to_string()
+        } else {
+            reload_path.unwrap_or_else(|| "/x.js".to_string())
         };
+        rebuild_done_handling(&broadcaster, result, &final_reload_path);
+        console::report_elapsed_time(start);
     }
+
+    // When we're the only thing running, we own the output directory's
+    // lifetime: clean it up here. `serve()` does this itself once all three
+    // of its threads (including this one) have stopped.
+    if own_ctrlc_handler {
+        let _ = remove_dir_all(Path::new(output_dir));
+    }
+
+    Ok(())
 }
 
 /// Returns whether the path we received corresponds to a temp file created
@@ -185,8 +349,13 @@ fn is_temp_file(path: &Path) -> bool {
 }
 
 /// Detect what changed from the given path so we have an idea what needs
-/// to be reloaded
-fn detect_change_kind(pwd: &Path, path: &Path) -> (ChangeKind, PathBuf) {
+/// to be reloaded.
+///
+/// Returns `None` rather than panicking when the path falls outside the
+/// folders we know how to handle, since the watcher can overlap with paths
+/// (e.g. an `output_dir` nested under one of the watched folders) that
+/// should simply be skipped instead of killing the server.
+fn detect_change_kind(pwd: &Path, path: &Path) -> Option<(ChangeKind, PathBuf)> {
     let mut partial_path = PathBuf::from("/");
     partial_path.push(path.strip_prefix(pwd).unwrap_or(path));
 
@@ -201,17 +370,38 @@ fn detect_change_kind(pwd: &Path, path: &Path) -> (ChangeKind, PathBuf) {
     } else if partial_path == Path::new("/config.toml") {
         ChangeKind::Config
     } else {
-        unreachable!("Got a change in an unexpected path: {}", partial_path.display());
+        return None;
     };
 
-    (change_kind, partial_path)
+    Some((change_kind, partial_path))
+}
+
+/// Map a changed source path to the URL livereload should swap in, so a
+/// Sass or static asset edit can hot-swap instead of forcing a full page
+/// refresh.
+fn reload_url(change_kind: &ChangeKind, partial_path: &Path) -> String {
+    match *change_kind {
+        ChangeKind::Sass => {
+            let mut css_path = PathBuf::from("/");
+            css_path.push(partial_path.strip_prefix("/sass").unwrap_or(partial_path));
+            css_path.set_extension("css");
+            css_path.to_string_lossy().into_owned()
+        },
+        ChangeKind::StaticFiles => {
+            let mut static_path = PathBuf::from("/");
+            static_path.push(partial_path.strip_prefix("/static").unwrap_or(partial_path));
+            static_path.to_string_lossy().into_owned()
+        },
+        // Content and templates always force a full reload
+        _ => "/x.js".to_string(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::{Path, PathBuf};
 
-    use super::{is_temp_file, detect_change_kind, ChangeKind};
+    use super::{is_temp_file, detect_change_kind, reload_url, ChangeKind};
 
     #[test]
     fn can_recognize_temp_files() {
@@ -236,23 +426,23 @@ mod tests {
     fn can_detect_kind_of_changes() {
         let test_cases = vec![
             (
-                (ChangeKind::Templates, PathBuf::from("/templates/hello.html")),
+                Some((ChangeKind::Templates, PathBuf::from("/templates/hello.html"))),
                 Path::new("/home/vincent/site"), Path::new("/home/vincent/site/templates/hello.html")
             ),
             (
-                (ChangeKind::StaticFiles, PathBuf::from("/static/site.css")),
+                Some((ChangeKind::StaticFiles, PathBuf::from("/static/site.css"))),
                 Path::new("/home/vincent/site"), Path::new("/home/vincent/site/static/site.css")
             ),
             (
-                (ChangeKind::Content, PathBuf::from("/content/posts/hello.md")),
+                Some((ChangeKind::Content, PathBuf::from("/content/posts/hello.md"))),
                 Path::new("/home/vincent/site"), Path::new("/home/vincent/site/content/posts/hello.md")
             ),
             (
-                (ChangeKind::Sass, PathBuf::from("/sass/print.scss")),
+                Some((ChangeKind::Sass, PathBuf::from("/sass/print.scss"))),
                 Path::new("/home/vincent/site"), Path::new("/home/vincent/site/sass/print.scss")
             ),
             (
-                (ChangeKind::Config, PathBuf::from("/config.toml")),
+                Some((ChangeKind::Config, PathBuf::from("/config.toml"))),
                 Path::new("/home/vincent/site"), Path::new("/home/vincent/site/config.toml")
             ),
         ];
@@ -265,15 +455,40 @@ mod tests {
     #[test]
     #[cfg(windows)]
     fn windows_path_handling() {
-        let expected = (ChangeKind::Templates, PathBuf::from("/templates/hello.html"));
+        let expected = Some((ChangeKind::Templates, PathBuf::from("/templates/hello.html")));
         let pwd = Path::new(r#"C:\\Users\johan\site"#);
         let path = Path::new(r#"C:\\Users\johan\site\templates\hello.html"#);
         assert_eq!(expected, detect_change_kind(pwd, path));
     }
 
+    #[test]
+    fn unexpected_paths_are_ignored_rather_than_panicking() {
+        let pwd = Path::new("/home/vincent/site");
+        let path = Path::new("/home/vincent/site/some_other_folder/hello.txt");
+        assert_eq!(None, detect_change_kind(pwd, path));
+    }
+
+    #[test]
+    fn can_compute_reload_url_for_sass() {
+        let url = reload_url(&ChangeKind::Sass, Path::new("/sass/print.scss"));
+        assert_eq!(url, "/print.css");
+    }
+
+    #[test]
+    fn can_compute_reload_url_for_static_files() {
+        let url = reload_url(&ChangeKind::StaticFiles, Path::new("/static/site.css"));
+        assert_eq!(url, "/site.css");
+    }
+
+    #[test]
+    fn reload_url_falls_back_to_full_reload() {
+        let url = reload_url(&ChangeKind::Content, Path::new("/content/posts/hello.md"));
+        assert_eq!(url, "/x.js");
+    }
+
     #[test]
     fn relative_path() {
-        let expected = (ChangeKind::Templates, PathBuf::from("/templates/hello.html"));
+        let expected = Some((ChangeKind::Templates, PathBuf::from("/templates/hello.html")));
         let pwd = Path::new("/home/johan/site");
         let path = Path::new("templates/hello.html");
         assert_eq!(expected, detect_change_kind(pwd, path));